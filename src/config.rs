@@ -0,0 +1,105 @@
+use anyhow::Error;
+use async_std::fs;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+/// A single named zone entry from the config file. `token` is optional so a
+/// zone can share the file's global token instead of carrying its own.
+#[derive(Debug, Deserialize)]
+pub struct ZoneConfig {
+    pub zone_id: String,
+    pub token: Option<String>,
+}
+
+/// Global API token plus a map of named zones, loaded from a TOML file like:
+///
+/// ```toml
+/// token = "global-api-token"
+///
+/// [zones.home]
+/// zone_id = "abcdef..."
+///
+/// [zones.work]
+/// zone_id = "123456..."
+/// token = "zone-specific-token"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub token: Option<String>,
+    #[serde(default)]
+    pub zones: HashMap<String, ZoneConfig>,
+}
+
+impl Config {
+    pub async fn load(path: &str) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).await?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Resolve a named zone to the `(zone_id, token)` pair to use against
+    /// the Cloudflare API, falling back to the file's global token when the
+    /// zone doesn't carry its own.
+    pub fn resolve(&self, zone_name: &str) -> Option<(String, String)> {
+        let zone = self.zones.get(zone_name)?;
+        let token = zone.token.clone().or_else(|| self.token.clone())?;
+        Some((zone.zone_id.clone(), token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(global_token: Option<&str>) -> Config {
+        let mut zones = HashMap::new();
+        zones.insert(
+            "home".to_string(),
+            ZoneConfig {
+                zone_id: "home-zone".to_string(),
+                token: None,
+            },
+        );
+        zones.insert(
+            "work".to_string(),
+            ZoneConfig {
+                zone_id: "work-zone".to_string(),
+                token: Some("work-token".to_string()),
+            },
+        );
+        Config {
+            token: global_token.map(str::to_string),
+            zones,
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_global_token() {
+        let config = config(Some("global-token"));
+        assert_eq!(
+            config.resolve("home"),
+            Some(("home-zone".to_string(), "global-token".to_string()))
+        );
+    }
+
+    #[test]
+    fn prefers_the_zone_s_own_token() {
+        let config = config(Some("global-token"));
+        assert_eq!(
+            config.resolve("work"),
+            Some(("work-zone".to_string(), "work-token".to_string()))
+        );
+    }
+
+    #[test]
+    fn none_when_zone_is_unknown() {
+        let config = config(Some("global-token"));
+        assert_eq!(config.resolve("nonexistent"), None);
+    }
+
+    #[test]
+    fn none_when_neither_zone_nor_global_has_a_token() {
+        let config = config(None);
+        assert_eq!(config.resolve("home"), None);
+    }
+}
@@ -1,23 +1,34 @@
 extern crate anyhow;
+extern crate async_native_tls;
 extern crate async_std;
+extern crate futures;
 extern crate log;
 extern crate pest;
 extern crate pest_derive;
 extern crate pretty_env_logger;
 extern crate serde;
 extern crate serde_json;
+extern crate sled;
 extern crate surf;
+extern crate tabled;
+extern crate toml;
+extern crate uuid;
 
+mod config;
 mod parser;
+mod retry;
 mod update;
 
-use anyhow::Error;
+use anyhow::{anyhow, bail, Error};
 use async_std::fs;
 use clap::Clap;
 use log::{debug, info};
+use tabled::Table;
 
+use config::Config;
 use parser::NSUpdateQueue;
-use update::RequestQueue;
+use retry::RetryQueue;
+use update::{CFCurrentRecords, RequestQueue};
 
 use std::env;
 use std::panic;
@@ -32,9 +43,79 @@ const PKG_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 
 #[derive(Clap)]
 #[clap(version = PKG_VERSION, about = PKG_DESCRIPTION)]
-struct Opts {
-    #[clap(short = "f", long = "file", about = "Path to nsupdate file")]
-    file: String,
+enum Opts {
+    /// Apply an nsupdate file to Cloudflare
+    Run(RunOpts),
+    /// List the current DNS records for a zone
+    List(ListOpts),
+}
+
+#[derive(Clap)]
+struct RunOpts {
+    #[clap(
+        short = "f",
+        long = "file",
+        about = "Path to nsupdate file; optional when --retry is set"
+    )]
+    file: Option<String>,
+    #[clap(
+        short = "z",
+        long = "zone",
+        about = "Zone ID retrieved from Cloudflare; overrides any `zone` directive and --config for every request"
+    )]
+    zone_id: Option<String>,
+    #[clap(
+        short = "t",
+        long = "token",
+        about = "Token retrieved from Cloudflare; required together with --zone"
+    )]
+    token: Option<String>,
+    #[clap(
+        short = "c",
+        long = "config",
+        about = "Path to a TOML file mapping `zone` directive names to Cloudflare zone IDs/tokens"
+    )]
+    config: Option<String>,
+    #[clap(
+        short = "v",
+        long = "verbose",
+        about = "Verbose level",
+        parse(from_occurrences)
+    )]
+    verbose: i8,
+    #[clap(
+        long = "ipv4-reflector",
+        about = "HTTP endpoint returning the caller's public IPv4 address, used to resolve @IPV4@ placeholders",
+        default_value = "https://v4.ident.me"
+    )]
+    ipv4_reflector: String,
+    #[clap(
+        long = "ipv6-reflector",
+        about = "HTTP endpoint returning the caller's public IPv6 address, used to resolve @IPV6@ placeholders",
+        default_value = "https://v6.ident.me"
+    )]
+    ipv6_reflector: String,
+    #[clap(
+        long = "concurrency",
+        about = "How many Cloudflare requests to have in flight at once",
+        default_value = "4"
+    )]
+    concurrency: usize,
+    #[clap(
+        long = "retry",
+        about = "Only retry requests from the persisted retry queue, skipping --file"
+    )]
+    retry_only: bool,
+    #[clap(
+        long = "retry-db",
+        about = "Path to the sled database used to persist failed requests for retry",
+        default_value = "nsupdate_cloudflare_retry.db"
+    )]
+    retry_db: String,
+}
+
+#[derive(Clap)]
+struct ListOpts {
     #[clap(
         short = "z",
         long = "zone",
@@ -50,13 +131,29 @@ struct Opts {
         parse(from_occurrences)
     )]
     verbose: i8,
+    #[clap(long = "name", about = "Only show records whose name contains this")]
+    name: Option<String>,
+    #[clap(long = "type", about = "Only show records of this type")]
+    record_type: Option<String>,
 }
 
-async fn execute(input: String, zone_id: &str, token: &str) -> Result<(), Error> {
+async fn execute(
+    input: String,
+    config: Option<&Config>,
+    override_zone: Option<(String, String)>,
+    ipv4_reflector: &str,
+    ipv6_reflector: &str,
+    concurrency: usize,
+    retry_queue: &RetryQueue,
+) -> Result<(), Error> {
     let mut input_text = Some(input);
     let mut batch_count: usize = 0;
     let mut total: usize = 0;
     let mut total_failed: usize = 0;
+    // Carries the last-seen `zone` directive across `send`-delimited
+    // batches, since one nsupdate file can have several of them under a
+    // single `zone`.
+    let mut carry_zone: Option<String> = None;
     while input_text.is_some() {
         // Well, too much hassle for using recursion in async fn
         batch_count += 1;
@@ -69,13 +166,23 @@ async fn execute(input: String, zone_id: &str, token: &str) -> Result<(), Error>
         );
         debug!("Parse result: {:?}", &parse_result);
         if parse_result.has_send().await {
-            let request_queue = RequestQueue::from(parse_result);
-            let (subtotal, subtotal_failed) = request_queue.process(zone_id, token).await?;
-            total += subtotal;
-            total_failed += total_failed;
+            let (request_queue, next_zone) = RequestQueue::from_batch(parse_result, carry_zone.clone());
+            carry_zone = next_zone;
+            let summary = request_queue
+                .process(
+                    config,
+                    override_zone.clone(),
+                    ipv4_reflector,
+                    ipv6_reflector,
+                    concurrency,
+                    retry_queue,
+                )
+                .await?;
+            total += summary.total();
+            total_failed += summary.failed;
             info!(
-                "Batch {} Subtotal: Processed {} requests, {} failed",
-                batch_count, subtotal, subtotal_failed
+                "Batch {} Subtotal: {} created, {} updated, {} unchanged, {} deleted, {} failed",
+                batch_count, summary.created, summary.updated, summary.unchanged, summary.deleted, summary.failed
             );
         } else {
             info!("No \"send\" command found, nothing to do...");
@@ -89,6 +196,26 @@ async fn execute(input: String, zone_id: &str, token: &str) -> Result<(), Error>
     Ok(())
 }
 
+async fn list(zone_id: &str, token: &str, name: Option<&str>, record_type: Option<&str>) -> Result<(), Error> {
+    let current_records = CFCurrentRecords::fetch(zone_id, token).await?;
+    let rows = current_records.rows(name, record_type);
+    println!("{}", Table::new(rows));
+    Ok(())
+}
+
+/// Set up the `NSUPDATE_CLOUDFLARE_LOG` logger from a `-v`/`-vv` verbosity count.
+fn setup_logger(verbose: i8) -> Result<(), Error> {
+    if env::var(PKG_LOG_LEVEL_VAR).is_err() {
+        match verbose {
+            0 => env::set_var(PKG_LOG_LEVEL_VAR, PKG_LOG_LEVEL_DEFAULT),
+            1 => env::set_var(PKG_LOG_LEVEL_VAR, PKG_LOG_LEVEL_VERBOSE_1),
+            2 | _ => env::set_var(PKG_LOG_LEVEL_VAR, PKG_LOG_LEVEL_VERBOSE_2),
+        }
+    }
+    pretty_env_logger::try_init_custom_env(PKG_LOG_LEVEL_VAR)?;
+    Ok(())
+}
+
 /// Set panic hook with repository information
 fn setup_panic_hook() {
     panic::set_hook(Box::new(|panic_info: &panic::PanicInfo| {
@@ -117,17 +244,56 @@ async fn main() -> Result<(), Error> {
     setup_panic_hook();
     // Parse command line options
     let opts: Opts = Opts::parse();
-    // Setup logger
-    if env::var(PKG_LOG_LEVEL_VAR).is_err() {
-        match opts.verbose {
-            0 => env::set_var(PKG_LOG_LEVEL_VAR, PKG_LOG_LEVEL_DEFAULT),
-            1 => env::set_var(PKG_LOG_LEVEL_VAR, PKG_LOG_LEVEL_VERBOSE_1),
-            2 | _ => env::set_var(PKG_LOG_LEVEL_VAR, PKG_LOG_LEVEL_VERBOSE_2),
+    match opts {
+        Opts::Run(run_opts) => {
+            setup_logger(run_opts.verbose)?;
+            let override_zone = match (run_opts.zone_id, run_opts.token) {
+                (Some(zone_id), Some(token)) => Some((zone_id, token)),
+                (None, None) => None,
+                _ => bail!("--zone and --token must be given together"),
+            };
+            let config = match run_opts.config {
+                Some(path) => Some(Config::load(&path).await?),
+                None => None,
+            };
+            let retry_queue = RetryQueue::open(&run_opts.retry_db)?;
+            info!("Retrying previously failed requests...");
+            RequestQueue::retry_pending(
+                &retry_queue,
+                &run_opts.ipv4_reflector,
+                &run_opts.ipv6_reflector,
+                run_opts.concurrency,
+            )
+            .await?;
+            if run_opts.retry_only {
+                return Ok(());
+            }
+            let file = run_opts
+                .file
+                .ok_or_else(|| anyhow!("--file is required unless --retry is set"))?;
+            info!("Reading nsupdate file...");
+            let unparsed_file = fs::read_to_string(file).await?;
+            info!("Start parsing...");
+            execute(
+                unparsed_file,
+                config.as_ref(),
+                override_zone,
+                &run_opts.ipv4_reflector,
+                &run_opts.ipv6_reflector,
+                run_opts.concurrency,
+                &retry_queue,
+            )
+            .await
+        }
+        Opts::List(list_opts) => {
+            setup_logger(list_opts.verbose)?;
+            list(
+                &list_opts.zone_id,
+                &list_opts.token,
+                list_opts.name.as_deref(),
+                list_opts.record_type.as_deref(),
+            )
+            .await
         }
     }
-    pretty_env_logger::try_init_custom_env(PKG_LOG_LEVEL_VAR)?;
-    info!("Reading nsupdate file...");
-    let unparsed_file = fs::read_to_string(opts.file).await?;
-    info!("Start parsing...");
-    execute(unparsed_file, &opts.zone_id, &opts.token).await
 }
@@ -1,12 +1,68 @@
+use pest::iterators::Pairs;
 use pest::Parser;
 use pest_derive::Parser;
 
 use anyhow::Error;
 
+use std::fmt;
+
 #[derive(Parser)]
 #[grammar = "nsupdate.pest"]
 struct NSUpdateParser;
 
+/// Precise, non-panicking diagnostics for a malformed nsupdate file.
+/// `MissingField` and `InvalidField` carry the line number (counted from the
+/// start of the current `send`-delimited batch), column and offending text;
+/// `Parse` wraps pest's own error, positioned within that single line.
+/// `parse_text` returns the first such error as an `Err` instead of aborting
+/// the whole program through a panic; lines after it are not parsed.
+#[derive(Debug)]
+pub enum NSUpdateError {
+    Parse(Box<pest::error::Error<Rule>>),
+    MissingField {
+        field: &'static str,
+        line_number: usize,
+        column: usize,
+        line: String,
+    },
+    InvalidField {
+        field: &'static str,
+        line_number: usize,
+        column: usize,
+        line: String,
+    },
+}
+
+impl fmt::Display for NSUpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NSUpdateError::Parse(err) => write!(f, "{}", err),
+            NSUpdateError::MissingField {
+                field,
+                line_number,
+                column,
+                line,
+            } => write!(
+                f,
+                "line {}, column {}: missing `{}` field in {:?}",
+                line_number, column, field, line
+            ),
+            NSUpdateError::InvalidField {
+                field,
+                line_number,
+                column,
+                line,
+            } => write!(
+                f,
+                "line {}, column {}: invalid `{}` field in {:?}",
+                line_number, column, field, line
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NSUpdateError {}
+
 #[derive(Debug)]
 pub struct NSUpdateActionAdd {
     pub domain: String,
@@ -32,12 +88,20 @@ pub enum NSUpdateAction {
 pub enum NSUpdateCommand {
     Update(NSUpdateAction),
     Send,
+    /// A `zone <name>` directive, selecting which configured zone the
+    /// following `update` commands apply to.
+    Zone(String),
+    /// A `server <host>` directive. Parsed for compatibility with standard
+    /// nsupdate files but otherwise informational, since zone routing is
+    /// driven by `Zone` directives and the config file instead.
+    Server(String),
 }
 
 #[derive(Debug, Default)]
 pub struct NSUpdateQueue {
     inner: Vec<NSUpdateCommand>,
     send: bool,
+    line_number: usize,
 }
 
 impl NSUpdateQueue {
@@ -88,47 +152,69 @@ impl NSUpdateQueue {
 
     // These are surely garbage code, but it just werks.
     pub async fn parse_command(&mut self, input: &str) -> Result<(), Error> {
-        let input_pairs = NSUpdateParser::parse(Rule::line, input)?;
+        self.line_number += 1;
+        let line_number = self.line_number;
+        let input_pairs = NSUpdateParser::parse(Rule::line, input)
+            .map_err(|err| NSUpdateError::Parse(Box::new(err)))?;
         for command in input_pairs {
             match command.as_rule() {
                 Rule::update => {
                     for action in command.into_inner() {
+                        let action_span = action.as_span();
                         self.push(NSUpdateCommand::Update({
                             match action.as_rule() {
                                 Rule::add => {
                                     let mut parameters = action.into_inner();
-                                    let domain = parameters.next().unwrap().as_str().to_string();
-                                    let ttl = parameters.next().unwrap().as_str().parse()?;
-                                    let record_type = parameters
-                                        .clone()
-                                        .skip(1)
-                                        .next()
-                                        .unwrap()
-                                        .as_str()
-                                        .to_string();
-                                    let (priority, content) = if parameters
-                                        .clone()
-                                        .skip(3)
-                                        .next()
-                                        .is_none()
-                                    {
-                                        (
-                                            None,
-                                            parameters.skip(2).next().unwrap().as_str().to_string(),
-                                        )
-                                    } else {
-                                        (
-                                            Some(
-                                                parameters
-                                                    .clone()
-                                                    .skip(2)
-                                                    .next()
-                                                    .unwrap()
-                                                    .as_str()
-                                                    .parse()?,
-                                            ),
-                                            parameters.skip(3).next().unwrap().as_str().to_string(),
-                                        )
+                                    let domain = next_field(
+                                        &mut parameters,
+                                        "domain",
+                                        line_number,
+                                        input,
+                                        &action_span,
+                                    )?
+                                    .as_str()
+                                    .to_string();
+                                    let ttl_pair = next_field(
+                                        &mut parameters,
+                                        "ttl",
+                                        line_number,
+                                        input,
+                                        &action_span,
+                                    )?;
+                                    let ttl = parse_field(&ttl_pair, "ttl", line_number, input)?;
+                                    // The record class (always "IN") carries no information we need.
+                                    next_field(
+                                        &mut parameters,
+                                        "class",
+                                        line_number,
+                                        input,
+                                        &action_span,
+                                    )?;
+                                    let record_type = next_field(
+                                        &mut parameters,
+                                        "type",
+                                        line_number,
+                                        input,
+                                        &action_span,
+                                    )?
+                                    .as_str()
+                                    .to_string();
+                                    let rest: Vec<_> = parameters.collect();
+                                    let (priority, content) = match rest.as_slice() {
+                                        [content] => (None, content.as_str().to_string()),
+                                        [priority, content] => (
+                                            Some(parse_field(priority, "priority", line_number, input)?),
+                                            content.as_str().to_string(),
+                                        ),
+                                        _ => {
+                                            return Err(NSUpdateError::MissingField {
+                                                field: "content",
+                                                line_number,
+                                                column: action_span.end_pos().line_col().1,
+                                                line: input.to_string(),
+                                            }
+                                            .into())
+                                        }
                                     };
                                     NSUpdateAction::Add(NSUpdateActionAdd {
                                         domain,
@@ -141,12 +227,24 @@ impl NSUpdateQueue {
                                 Rule::delete => {
                                     let mut parameters = action.into_inner();
                                     NSUpdateAction::Delete(NSUpdateActionDelete {
-                                        domain: parameters.next().unwrap().as_str().to_string(),
-                                        record_type: parameters
-                                            .next()
-                                            .unwrap()
-                                            .as_str()
-                                            .to_string(),
+                                        domain: next_field(
+                                            &mut parameters,
+                                            "domain",
+                                            line_number,
+                                            input,
+                                            &action_span,
+                                        )?
+                                        .as_str()
+                                        .to_string(),
+                                        record_type: next_field(
+                                            &mut parameters,
+                                            "type",
+                                            line_number,
+                                            input,
+                                            &action_span,
+                                        )?
+                                        .as_str()
+                                        .to_string(),
                                     })
                                 }
                                 _ => unreachable!(),
@@ -159,6 +257,22 @@ impl NSUpdateQueue {
                     self.push(NSUpdateCommand::Send).await;
                     self.set_send().await;
                 }
+                Rule::zone => {
+                    let command_span = command.as_span();
+                    let mut parameters = command.into_inner();
+                    let name = next_field(&mut parameters, "zone", line_number, input, &command_span)?
+                        .as_str()
+                        .to_string();
+                    self.push(NSUpdateCommand::Zone(name)).await;
+                }
+                Rule::server => {
+                    let command_span = command.as_span();
+                    let mut parameters = command.into_inner();
+                    let host = next_field(&mut parameters, "server", line_number, input, &command_span)?
+                        .as_str()
+                        .to_string();
+                    self.push(NSUpdateCommand::Server(host)).await;
+                }
                 Rule::EOI | Rule::WHITESPACE | Rule::COMMENT => continue,
                 _ => unreachable!(),
             }
@@ -166,3 +280,86 @@ impl NSUpdateQueue {
         Ok(())
     }
 }
+
+/// Pull the next pair out of `parameters`, reporting a precise
+/// `NSUpdateError::MissingField` (anchored to the end of `context_span`)
+/// instead of panicking when the field isn't there.
+fn next_field<'i>(
+    parameters: &mut Pairs<'i, Rule>,
+    field: &'static str,
+    line_number: usize,
+    line: &str,
+    context_span: &pest::Span<'i>,
+) -> Result<pest::iterators::Pair<'i, Rule>, NSUpdateError> {
+    parameters.next().ok_or_else(|| NSUpdateError::MissingField {
+        field,
+        line_number,
+        column: context_span.end_pos().line_col().1,
+        line: line.to_string(),
+    })
+}
+
+/// Parse a pair's text as `T`, reporting a precise `NSUpdateError::InvalidField`
+/// (anchored to the pair's own span) instead of bubbling up a raw parse error.
+fn parse_field<T: std::str::FromStr>(
+    pair: &pest::iterators::Pair<Rule>,
+    field: &'static str,
+    line_number: usize,
+    line: &str,
+) -> Result<T, NSUpdateError> {
+    pair.as_str().parse().map_err(|_| NSUpdateError::InvalidField {
+        field,
+        line_number,
+        column: pair.as_span().start_pos().line_col().1,
+        line: line.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone_domain_pairs(line: &str) -> (pest::Span<'_>, Pairs<'_, Rule>) {
+        let mut pairs = NSUpdateParser::parse(Rule::zone, line).expect("zone line parses");
+        let zone = pairs.next().expect("has a zone pair");
+        (zone.as_span(), zone.into_inner())
+    }
+
+    #[test]
+    fn next_field_returns_the_pair() {
+        let (span, mut parameters) = zone_domain_pairs("zone example.com");
+        let domain = next_field(&mut parameters, "domain", 1, "zone example.com", &span)
+            .expect("domain is present");
+        assert_eq!(domain.as_str(), "example.com");
+    }
+
+    #[test]
+    fn next_field_errors_when_exhausted() {
+        let (span, mut parameters) = zone_domain_pairs("zone example.com");
+        parameters.next();
+        let err = next_field(&mut parameters, "domain", 1, "zone example.com", &span).unwrap_err();
+        assert!(matches!(err, NSUpdateError::MissingField { field: "domain", .. }));
+    }
+
+    #[test]
+    fn parse_field_parses_a_valid_value() {
+        let line = "add example.com 300 IN A 1.2.3.4";
+        let mut pairs = NSUpdateParser::parse(Rule::add, line).expect("add line parses");
+        let add = pairs.next().expect("has an add pair");
+        let mut inner = add.into_inner();
+        inner.next();
+        let ttl_pair = inner.next().expect("has a ttl pair");
+        let ttl: usize = parse_field(&ttl_pair, "ttl", 1, line).expect("ttl parses");
+        assert_eq!(ttl, 300);
+    }
+
+    #[test]
+    fn parse_field_errors_on_invalid_value() {
+        let line = "add example.com 300 IN A 1.2.3.4";
+        let mut pairs = NSUpdateParser::parse(Rule::add, line).expect("add line parses");
+        let add = pairs.next().expect("has an add pair");
+        let domain_pair = add.into_inner().next().expect("has a domain pair");
+        let err = parse_field::<usize>(&domain_pair, "domain", 1, line).unwrap_err();
+        assert!(matches!(err, NSUpdateError::InvalidField { field: "domain", .. }));
+    }
+}
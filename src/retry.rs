@@ -0,0 +1,49 @@
+use anyhow::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::IVec;
+use uuid::Uuid;
+
+const RETRY_TREE_NAME: &str = "pending_retries";
+
+/// Durable store of requests that failed to apply, so a flaky network
+/// doesn't silently drop a change. Backed by an embedded `sled` tree keyed
+/// by a generated UUID.
+pub struct RetryQueue {
+    tree: sled::Tree,
+}
+
+impl RetryQueue {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let db = sled::open(path)?;
+        let tree = db.open_tree(RETRY_TREE_NAME)?;
+        Ok(Self { tree })
+    }
+
+    /// Persist an item under a fresh UUID key.
+    pub fn persist<T: Serialize>(&self, item: &T) -> Result<(), Error> {
+        let id = Uuid::new_v4();
+        self.tree.insert(id.as_bytes(), serde_json::to_vec(item)?)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// Load every persisted item, dropping (and logging) any entry that no
+    /// longer deserializes cleanly rather than failing the whole batch.
+    pub fn drain<T: DeserializeOwned>(&self) -> Result<Vec<(IVec, T)>, Error> {
+        let mut pending = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            match serde_json::from_slice::<T>(&value) {
+                Ok(item) => pending.push((key, item)),
+                Err(err) => log::warn!("Dropping corrupt retry entry: {:?}", err),
+            }
+        }
+        Ok(pending)
+    }
+
+    pub fn remove(&self, key: &IVec) -> Result<(), Error> {
+        self.tree.remove(key)?;
+        Ok(())
+    }
+}
@@ -1,15 +1,121 @@
-use anyhow::{bail, Error};
+use anyhow::{anyhow, bail, Error};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpStream, ToSocketAddrs};
+use async_std::task;
+use futures::stream::{self, StreamExt};
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sled::IVec;
+use tabled::Tabled;
 
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::config::Config;
 use super::parser::NSUpdateAction;
 use super::parser::NSUpdateActionAdd;
 use super::parser::NSUpdateActionDelete;
 use super::parser::NSUpdateCommand;
 use super::parser::NSUpdateQueue;
+use super::retry::RetryQueue;
+
+// How many times a persisted retry is re-attempted (with exponential
+// backoff) before it's left in the queue for the next run to pick up.
+const MAX_RETRY_ATTEMPTS: u32 = 5;
+
+// Placeholder tokens recognized in an Add action's `content`. When one of
+// these is seen, the literal value is swapped out for the caller's current
+// public address as fetched from the matching reflector endpoint, turning a
+// static nsupdate file into a DDNS update.
+const PLACEHOLDER_IPV4: &str = "@IPV4@";
+const PLACEHOLDER_IPV6: &str = "@IPV6@";
+
+/// Which address family to force a reflector request over.
+#[derive(Clone, Copy, Debug)]
+enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
 
-#[derive(Debug, Serialize)]
+/// Split a `http(s)://host[:port][/path]` reflector URL into its parts.
+/// Only what a reflector endpoint needs is supported - no query-string
+/// parsing beyond keeping it as part of the path, no userinfo, no IPv6
+/// literal hosts.
+fn parse_reflector_url(url: &str) -> Result<(bool, String, u16, String), Error> {
+    let (is_https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        bail!("reflector URL must start with http:// or https://: {}", url);
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host.to_string(),
+            port.parse()
+                .map_err(|_| anyhow!("invalid port in reflector URL: {}", url))?,
+        ),
+        None => (authority.to_string(), if is_https { 443 } else { 80 }),
+    };
+    Ok((is_https, host, port, path.to_string()))
+}
+
+/// Ask a reflector endpoint what public address it saw the request come
+/// from, connecting over exactly the requested address `family` rather
+/// than letting the OS pick whichever one `host` resolves to first - the
+/// only way to get a reliable answer out of a reflector hostname that
+/// happens to have both A and AAAA records.
+async fn fetch_public_ip(reflector_url: &str, family: IpFamily) -> Result<String, Error> {
+    #[derive(Deserialize)]
+    struct ReflectorJson {
+        ip: String,
+    }
+
+    let (is_https, host, port, path) = parse_reflector_url(reflector_url)?;
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()
+        .await?
+        .find(|addr| family.matches(addr))
+        .ok_or_else(|| anyhow!("{} has no {:?} address to connect to", host, family))?;
+    let tcp = TcpStream::connect(addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: nsupdate_cloudflare\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    let mut raw = Vec::new();
+    if is_https {
+        let mut tls = async_native_tls::connect(host.as_str(), tcp).await?;
+        tls.write_all(request.as_bytes()).await?;
+        tls.read_to_end(&mut raw).await?;
+    } else {
+        let mut tcp = tcp;
+        tcp.write_all(request.as_bytes()).await?;
+        tcp.read_to_end(&mut raw).await?;
+    };
+    let response = String::from_utf8_lossy(&raw);
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or("").trim();
+    match serde_json::from_str::<ReflectorJson>(body) {
+        Ok(parsed) => Ok(parsed.ip),
+        Err(_) => Ok(body.to_string()),
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RequestDataAdd {
     #[serde(rename = "type")]
     record_type: String,
@@ -20,7 +126,7 @@ pub struct RequestDataAdd {
     proxied: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RequestDataDelete {
     pub record_type: String,
     pub name: String,
@@ -28,7 +134,26 @@ pub struct RequestDataDelete {
 
 #[derive(Debug)]
 pub struct RequestQueue {
-    inner: Vec<RequestData>,
+    inner: Vec<QueuedRequest>,
+}
+
+/// A request paired with the name of the `zone` directive active when it
+/// was parsed, if any. `None` is only valid when `RequestQueue::process` is
+/// given an `override_zone` to fall back on.
+#[derive(Debug)]
+struct QueuedRequest {
+    zone: Option<String>,
+    data: RequestData,
+}
+
+/// What we actually persist for a failed request: the resolved zone it was
+/// headed for plus the request itself, so a later `--retry` run can send it
+/// to the right place without needing the original nsupdate file or config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PersistedRequest {
+    zone_id: String,
+    token: String,
+    data: RequestData,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -44,6 +169,10 @@ struct CFRecord {
     #[serde(default)]
     ttl: usize,
     #[serde(default)]
+    priority: Option<usize>,
+    #[serde(default)]
+    proxied: bool,
+    #[serde(default)]
     locked: bool,
     #[serde(default)]
     zone_id: String,
@@ -77,10 +206,40 @@ struct CFListResponse {
 }
 
 #[derive(Debug, Default)]
-struct CFCurrentRecords {
+pub struct CFCurrentRecords {
     inner: Vec<CFRecord>,
 }
 
+/// A single DNS record as rendered by the `list` subcommand.
+#[derive(Tabled)]
+pub struct CFRecordRow {
+    #[tabled(rename = "Name")]
+    name: String,
+    #[tabled(rename = "Type")]
+    record_type: String,
+    #[tabled(rename = "Content")]
+    content: String,
+    #[tabled(rename = "TTL")]
+    ttl: usize,
+    #[tabled(rename = "Proxied")]
+    proxied: bool,
+    #[tabled(rename = "ID")]
+    id: String,
+}
+
+impl From<&CFRecord> for CFRecordRow {
+    fn from(record: &CFRecord) -> Self {
+        Self {
+            name: record.name.clone(),
+            record_type: record.record_type.clone(),
+            content: record.content.clone(),
+            ttl: record.ttl,
+            proxied: record.proxied,
+            id: record.id.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct CFUpdateResponse {
     success: bool,
@@ -88,12 +247,78 @@ struct CFUpdateResponse {
     result: Option<CFRecord>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum RequestData {
     Add(RequestDataAdd),
     Delete(RequestDataDelete),
 }
 
+impl RequestData {
+    /// The `(name, record_type)` a request acts on, used to keep requests
+    /// that touch the same record in their original order.
+    fn key(&self) -> (String, String) {
+        match self {
+            Self::Add(add) => (add.name.clone(), add.record_type.clone()),
+            Self::Delete(delete) => (delete.name.clone(), delete.record_type.clone()),
+        }
+    }
+}
+
+/// What actually happened when a single request was sent to Cloudflare.
+#[derive(Debug)]
+enum SendOutcome {
+    Created,
+    Updated,
+    Unchanged,
+    Deleted,
+    Failed,
+}
+
+/// Aggregate counts across a whole `RequestQueue::process` run.
+#[derive(Debug, Default)]
+pub struct ProcessSummary {
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+    pub failed: usize,
+}
+
+impl ProcessSummary {
+    pub fn total(&self) -> usize {
+        self.created + self.updated + self.unchanged + self.deleted + self.failed
+    }
+
+    fn record(&mut self, outcome: &SendOutcome) {
+        match outcome {
+            SendOutcome::Created => self.created += 1,
+            SendOutcome::Updated => self.updated += 1,
+            SendOutcome::Unchanged => self.unchanged += 1,
+            SendOutcome::Deleted => self.deleted += 1,
+            SendOutcome::Failed => self.failed += 1,
+        }
+    }
+
+    /// Fold another zone's summary into this one, for totalling across a
+    /// multi-zone run.
+    fn merge(&mut self, other: Self) {
+        self.created += other.created;
+        self.updated += other.updated;
+        self.unchanged += other.unchanged;
+        self.deleted += other.deleted;
+        self.failed += other.failed;
+    }
+}
+
+/// Whether an existing record's content/ttl/priority/proxied state already
+/// match a pending Add, i.e. whether sending it would be a no-op.
+fn record_unchanged(record: &CFRecord, request: &RequestDataAdd) -> bool {
+    record.content == request.content
+        && record.ttl == request.ttl
+        && record.priority == request.priority
+        && record.proxied == request.proxied
+}
+
 impl From<NSUpdateActionAdd> for RequestDataAdd {
     fn from(source: NSUpdateActionAdd) -> Self {
         Self {
@@ -116,28 +341,39 @@ impl From<NSUpdateActionDelete> for RequestDataDelete {
     }
 }
 
-impl From<NSUpdateQueue> for RequestQueue {
-    fn from(source: NSUpdateQueue) -> Self {
-        Self {
-            inner: source
-                .into_inner()
-                .into_iter()
-                .filter_map(|command| {
-                    if let NSUpdateCommand::Update(update) = command {
-                        Some(match update {
-                            NSUpdateAction::Add(orig_add) => {
-                                RequestData::Add(RequestDataAdd::from(orig_add))
-                            }
-                            NSUpdateAction::Delete(orig_delete) => {
-                                RequestData::Delete(RequestDataDelete::from(orig_delete))
-                            }
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
+impl RequestQueue {
+    /// Build a request queue from one parsed batch of commands, seeding the
+    /// active zone with `carry_zone` - whatever `zone` directive was last
+    /// seen in an earlier batch of the same file, since a real nsupdate
+    /// file can hold several `send`-delimited batches under one `zone`.
+    /// Returns the zone active at the end of this batch so the caller can
+    /// carry it into the next one.
+    pub fn from_batch(source: NSUpdateQueue, carry_zone: Option<String>) -> (Self, Option<String>) {
+        let mut current_zone = carry_zone;
+        let mut inner = Vec::new();
+        for command in source.into_inner() {
+            match command {
+                NSUpdateCommand::Zone(name) => current_zone = Some(name),
+                // Informational only; zone routing comes from `Zone` directives and --config.
+                NSUpdateCommand::Server(_) => {}
+                NSUpdateCommand::Send => {}
+                NSUpdateCommand::Update(update) => {
+                    let data = match update {
+                        NSUpdateAction::Add(orig_add) => {
+                            RequestData::Add(RequestDataAdd::from(orig_add))
+                        }
+                        NSUpdateAction::Delete(orig_delete) => {
+                            RequestData::Delete(RequestDataDelete::from(orig_delete))
+                        }
+                    };
+                    inner.push(QueuedRequest {
+                        zone: current_zone.clone(),
+                        data,
+                    });
+                }
+            }
         }
+        (Self { inner }, current_zone)
     }
 }
 
@@ -147,10 +383,7 @@ impl CFListResponse {
             "https://api.cloudflare.com/client/v4/zones/{}/dns_records?per_page=1000&page={}",
             zone_id, page
         ))
-        .set_header(
-            "Authorization".parse().expect("Wut?"),
-            format!("Bearer {}", token),
-        )
+        .set_header("Authorization".parse()?, format!("Bearer {}", token))
         .recv_json()
         .await
         {
@@ -182,6 +415,25 @@ impl CFCurrentRecords {
         }
     }
 
+    /// Fetch every DNS record for a zone, for the `list` subcommand.
+    pub async fn fetch(zone_id: &str, token: &str) -> Result<Self, Error> {
+        let mut current_records = Self::new().await;
+        current_records.update(zone_id, token).await?;
+        Ok(current_records)
+    }
+
+    /// Render fetched records as rows, optionally filtered by name/type.
+    pub fn rows(&self, name: Option<&str>, record_type: Option<&str>) -> Vec<CFRecordRow> {
+        self.inner
+            .iter()
+            .filter(|record| {
+                name.map_or(true, |filter| record.name.contains(filter))
+                    && record_type.map_or(true, |filter| record.record_type.eq_ignore_ascii_case(filter))
+            })
+            .map(CFRecordRow::from)
+            .collect()
+    }
+
     async fn append(&mut self, result: &mut Vec<CFRecord>) {
         debug!(">>> Appending : {:?}", &result);
         self.inner.append(result);
@@ -234,21 +486,48 @@ impl CFCurrentRecords {
 }
 
 impl RequestDataAdd {
-    async fn send(self, zone_id: &str, token: &str) -> Result<Option<CFUpdateResponse>, Error> {
-        info!("Adding {}", self.name);
-        match surf::post(format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-            zone_id
-        ))
-        .set_header(
-            "Authorization".parse().expect("Wut?"),
-            format!("Bearer {}", token),
-        )
-        .body_json(&json!(self))?
-        .recv_json()
-        .await
+    /// Resolve any DDNS placeholder in `content` against the configured
+    /// reflector endpoints. No-op if `content` isn't a recognized token.
+    async fn resolve_content(
+        &mut self,
+        ipv4_reflector: &str,
+        ipv6_reflector: &str,
+    ) -> Result<(), Error> {
+        self.content = match self.content.as_str() {
+            PLACEHOLDER_IPV4 => fetch_public_ip(ipv4_reflector, IpFamily::V4).await?,
+            PLACEHOLDER_IPV6 => fetch_public_ip(ipv6_reflector, IpFamily::V6).await?,
+            _ => return Ok(()),
+        };
+        info!("Resolved DDNS placeholder for {} to {}", self.name, self.content);
+        Ok(())
+    }
+
+    /// POSTs a new record, or PUTs over an existing one when `record_id`
+    /// is given. Returns whether Cloudflare reported success.
+    async fn send(self, zone_id: &str, token: &str, record_id: Option<&str>) -> Result<bool, Error> {
+        let request = match record_id {
+            Some(id) => {
+                info!("Updating {}", self.name);
+                surf::put(format!(
+                    "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
+                    zone_id, id
+                ))
+            }
+            None => {
+                info!("Adding {}", self.name);
+                surf::post(format!(
+                    "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
+                    zone_id
+                ))
+            }
+        };
+        match request
+            .set_header("Authorization".parse()?, format!("Bearer {}", token))
+            .body_json(&json!(self))?
+            .recv_json::<CFUpdateResponse>()
+            .await
         {
-            Ok(response) => Ok(Some(response)),
+            Ok(response) => Ok(response.success),
             Err(err) => bail!(err),
         }
     }
@@ -262,19 +541,18 @@ impl RequestDataDelete {
         record_id: Option<&str>,
     ) -> Result<Option<CFUpdateResponse>, Error> {
         info!("Deleting {}", self.name);
-        if record_id.is_none() {
-            warn!("Record not found");
-            return Ok(None);
-        }
+        let record_id = match record_id {
+            Some(id) => id,
+            None => {
+                warn!("Record not found");
+                return Ok(None);
+            }
+        };
         match surf::delete(format!(
             "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-            zone_id,
-            record_id.expect("Wut?")
+            zone_id, record_id
         ))
-        .set_header(
-            "Authorization".parse().expect("Wut?"),
-            format!("Bearer {}", token),
-        )
+        .set_header("Authorization".parse()?, format!("Bearer {}", token))
         .recv_json()
         .await
         {
@@ -290,9 +568,43 @@ impl RequestData {
         zone_id: &str,
         token: &str,
         current_records: &CFCurrentRecords,
-    ) -> Result<Option<CFUpdateResponse>, Error> {
+        ipv4_reflector: &str,
+        ipv6_reflector: &str,
+    ) -> Result<SendOutcome, Error> {
         Ok(match self {
-            Self::Add(request_add) => request_add.send(zone_id, token).await?,
+            Self::Add(mut request_add) => {
+                request_add
+                    .resolve_content(ipv4_reflector, ipv6_reflector)
+                    .await?;
+                match current_records
+                    .find_record(&request_add.name, &request_add.record_type)
+                    .await?
+                {
+                    // Nsupdate lines have no way to express `proxied`, so an
+                    // update must carry forward whatever the existing record
+                    // already has rather than clobbering it back to `false`.
+                    Some(record) => {
+                        request_add.proxied = record.proxied;
+                        if record_unchanged(record, &request_add) {
+                            info!("{} already up to date, skipping", request_add.name);
+                            return Ok(SendOutcome::Unchanged);
+                        }
+                        let record_id = record.id.clone();
+                        if request_add.send(zone_id, token, Some(&record_id)).await? {
+                            SendOutcome::Updated
+                        } else {
+                            SendOutcome::Failed
+                        }
+                    }
+                    None => {
+                        if request_add.send(zone_id, token, None).await? {
+                            SendOutcome::Created
+                        } else {
+                            SendOutcome::Failed
+                        }
+                    }
+                }
+            }
             Self::Delete(request_delete) => {
                 let domain = request_delete.clone().name;
                 let record_type = request_delete.clone().record_type;
@@ -300,32 +612,291 @@ impl RequestData {
                     .find_record_id(&domain, &record_type)
                     .await?;
                 debug!("Record ID: {:?}, domain: {:?}", &record_id, &domain);
-                request_delete.send(zone_id, token, record_id).await?
+                match request_delete.send(zone_id, token, record_id).await? {
+                    Some(response) if response.success => SendOutcome::Deleted,
+                    _ => SendOutcome::Failed,
+                }
             }
         })
     }
 }
 
 impl RequestQueue {
-    pub async fn process(self, zone_id: &str, token: &str) -> Result<(usize, usize), Error> {
+    /// Resolve the `(zone_id, token)` a queued request should go to.
+    /// `override_zone` (set when both `--zone` and `--token` are given on
+    /// the command line) always wins; otherwise a request's `zone`
+    /// directive is looked up in `config`, and a request with no directive
+    /// at all is an error.
+    fn resolve_zone(
+        zone: &Option<String>,
+        config: Option<&Config>,
+        override_zone: &Option<(String, String)>,
+    ) -> Result<(String, String), Error> {
+        if let Some(zone_id_and_token) = override_zone {
+            return Ok(zone_id_and_token.clone());
+        }
+        match zone {
+            Some(name) => {
+                let config = config
+                    .ok_or_else(|| anyhow!("nsupdate file selects zone \"{}\" but no --config was given", name))?;
+                config
+                    .resolve(name)
+                    .ok_or_else(|| anyhow!("zone \"{}\" not found in --config", name))
+            }
+            None => Err(anyhow!(
+                "no zone to send to: pass --zone/--token, or add a `zone` directive and --config"
+            )),
+        }
+    }
+
+    /// Sends every queued request, `concurrency` at a time, grouped so that
+    /// every request bound for the same Cloudflare zone shares a single
+    /// `CFCurrentRecords` snapshot. All deletes resolve their record IDs
+    /// from that snapshot, taken up front, so the reads are safe to run in
+    /// parallel with it; requests sharing a (name, record_type) are kept in
+    /// order so a delete/add pair on the same record can't race each other.
+    /// Anything that fails is persisted into `retry_queue` instead of being
+    /// silently dropped.
+    pub async fn process(
+        self,
+        config: Option<&Config>,
+        override_zone: Option<(String, String)>,
+        ipv4_reflector: &str,
+        ipv6_reflector: &str,
+        concurrency: usize,
+        retry_queue: &RetryQueue,
+    ) -> Result<ProcessSummary, Error> {
+        let mut by_zone: HashMap<(String, String), Vec<RequestData>> = HashMap::new();
+        for queued in self.inner.into_iter() {
+            let zone_id_and_token = Self::resolve_zone(&queued.zone, config, &override_zone)?;
+            by_zone.entry(zone_id_and_token).or_default().push(queued.data);
+        }
+        let mut summary = ProcessSummary::default();
+        for ((zone_id, token), requests) in by_zone {
+            let zone_summary = Self::process_zone(
+                requests,
+                &zone_id,
+                &token,
+                ipv4_reflector,
+                ipv6_reflector,
+                concurrency,
+                retry_queue,
+            )
+            .await?;
+            summary.merge(zone_summary);
+        }
+        Ok(summary)
+    }
+
+    async fn process_zone(
+        requests: Vec<RequestData>,
+        zone_id: &str,
+        token: &str,
+        ipv4_reflector: &str,
+        ipv6_reflector: &str,
+        concurrency: usize,
+        retry_queue: &RetryQueue,
+    ) -> Result<ProcessSummary, Error> {
         let mut current_records = CFCurrentRecords::new().await;
         current_records.update(zone_id, token).await?;
-        let iterator = self.inner.into_iter();
-        let mut subtotal: usize = 0;
-        let mut subtotal_failed: usize = 0;
-        for request in iterator {
-            let result = request.send(zone_id, token, &current_records).await?;
-            subtotal += 1;
-            info!("Result: {}", {
-                if result.is_some() && result.clone().expect("Wut?").success {
-                    "SUCCESS"
-                } else {
-                    subtotal_failed += 1;
-                    "FAILED"
-                }
+        let current_records = &current_records;
+        // Requests sharing a (name, record_type) key - e.g. a `delete` of a
+        // record followed by an `add` re-creating it in the same batch -
+        // race each other if sent concurrently, since they both act on the
+        // same underlying Cloudflare record. Group by key and keep each
+        // group's requests in their original order; only independent keys
+        // run concurrently against each other.
+        let mut groups: Vec<Vec<RequestData>> = Vec::new();
+        let mut group_by_key: HashMap<(String, String), usize> = HashMap::new();
+        for request in requests {
+            let key = request.key();
+            let index = *group_by_key.entry(key).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
             });
-            debug!("Result: {:?}", result);
+            groups[index].push(request);
         }
-        Ok((subtotal, subtotal_failed))
+        let results: Vec<(RequestData, Result<SendOutcome, Error>)> = stream::iter(groups)
+            .map(|group| async move {
+                let mut results = Vec::with_capacity(group.len());
+                for request in group {
+                    let persisted = request.clone();
+                    let outcome = request
+                        .send(zone_id, token, current_records, ipv4_reflector, ipv6_reflector)
+                        .await;
+                    results.push((persisted, outcome));
+                }
+                results
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect::<Vec<Vec<_>>>()
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut summary = ProcessSummary::default();
+        for (request, result) in results {
+            let outcome = match result {
+                Ok(outcome) => outcome,
+                Err(err) => {
+                    warn!("Request errored: {:?}", err);
+                    SendOutcome::Failed
+                }
+            };
+            info!("Result: {:?}", outcome);
+            if let SendOutcome::Failed = outcome {
+                retry_queue.persist(&PersistedRequest {
+                    zone_id: zone_id.to_string(),
+                    token: token.to_string(),
+                    data: request,
+                })?;
+            }
+            summary.record(&outcome);
+        }
+        Ok(summary)
+    }
+
+    /// Re-attempt everything sitting in `retry_queue`, `concurrency` groups
+    /// at a time, with exponential backoff between attempts on each entry,
+    /// removing it once it succeeds. Each entry already carries the zone it
+    /// was headed for, so this needs no zone/config arguments of its own.
+    pub async fn retry_pending(
+        retry_queue: &RetryQueue,
+        ipv4_reflector: &str,
+        ipv6_reflector: &str,
+        concurrency: usize,
+    ) -> Result<(), Error> {
+        let pending: Vec<(IVec, PersistedRequest)> = retry_queue.drain()?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        info!("Retrying {} previously failed request(s)", pending.len());
+        let mut by_zone: HashMap<(String, String), Vec<(IVec, RequestData)>> = HashMap::new();
+        for (key, persisted) in pending {
+            by_zone
+                .entry((persisted.zone_id, persisted.token))
+                .or_default()
+                .push((key, persisted.data));
+        }
+        for ((zone_id, token), entries) in by_zone {
+            let mut current_records = CFCurrentRecords::new().await;
+            current_records.update(&zone_id, &token).await?;
+            let current_records = &current_records;
+            let zone_id = &zone_id;
+            let token = &token;
+
+            // Same (name, record_type) grouping as process_zone, so that
+            // e.g. a failed delete and a failed re-add of the same record
+            // don't get retried against each other out of order.
+            let mut groups: Vec<Vec<(IVec, RequestData)>> = Vec::new();
+            let mut group_by_key: HashMap<(String, String), usize> = HashMap::new();
+            for (key, request) in entries {
+                let group_key = request.key();
+                let index = *group_by_key.entry(group_key).or_insert_with(|| {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                });
+                groups[index].push((key, request));
+            }
+
+            let results: Vec<Result<(), Error>> = stream::iter(groups)
+                .map(|group| async move {
+                    for (key, request) in group {
+                        let mut attempt: u32 = 0;
+                        loop {
+                            attempt += 1;
+                            let result = request
+                                .clone()
+                                .send(zone_id, token, current_records, ipv4_reflector, ipv6_reflector)
+                                .await;
+                            let failed = matches!(result, Ok(SendOutcome::Failed)) | result.is_err();
+                            if !failed {
+                                info!("Retry succeeded: {:?}", result.expect("checked above"));
+                                retry_queue.remove(&key)?;
+                                break;
+                            } else if attempt < MAX_RETRY_ATTEMPTS {
+                                let backoff = Duration::from_secs(2u64.pow(attempt));
+                                warn!(
+                                    "Retry {} failed ({:?}), backing off {:?}",
+                                    attempt, result, backoff
+                                );
+                                task::sleep(backoff).await;
+                            } else {
+                                warn!("Giving up after {} attempts: {:?}", attempt, result);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+                .buffer_unordered(concurrency.max(1))
+                .collect()
+                .await;
+            for result in results {
+                result?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_add(content: &str, ttl: usize, priority: Option<usize>, proxied: bool) -> RequestDataAdd {
+        RequestDataAdd {
+            record_type: "A".to_string(),
+            name: "example.com".to_string(),
+            content: content.to_string(),
+            ttl,
+            priority,
+            proxied,
+        }
+    }
+
+    fn cf_record(content: &str, ttl: usize, priority: Option<usize>, proxied: bool) -> CFRecord {
+        CFRecord {
+            content: content.to_string(),
+            ttl,
+            priority,
+            proxied,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unchanged_when_everything_matches() {
+        let record = cf_record("1.2.3.4", 300, None, true);
+        let request = request_add("1.2.3.4", 300, None, true);
+        assert!(record_unchanged(&record, &request));
+    }
+
+    #[test]
+    fn changed_when_content_differs() {
+        let record = cf_record("1.2.3.4", 300, None, false);
+        let request = request_add("5.6.7.8", 300, None, false);
+        assert!(!record_unchanged(&record, &request));
+    }
+
+    #[test]
+    fn changed_when_ttl_differs() {
+        let record = cf_record("1.2.3.4", 300, None, false);
+        let request = request_add("1.2.3.4", 120, None, false);
+        assert!(!record_unchanged(&record, &request));
+    }
+
+    #[test]
+    fn changed_when_priority_differs() {
+        let record = cf_record("mail.example.com", 300, Some(10), false);
+        let request = request_add("mail.example.com", 300, Some(20), false);
+        assert!(!record_unchanged(&record, &request));
+    }
+
+    #[test]
+    fn changed_when_proxied_differs() {
+        let record = cf_record("1.2.3.4", 300, None, false);
+        let request = request_add("1.2.3.4", 300, None, true);
+        assert!(!record_unchanged(&record, &request));
     }
 }